@@ -19,7 +19,12 @@ fn main() {
     };
 
     match run(&input) {
-        Ok(term) => println!("{}", term),
+        Ok((term, warnings)) => {
+            for warning in &warnings {
+                display_error(&input, &options.path, warning);
+            }
+            println!("{}", term);
+        }
         Err(err) => display_error(&input, &options.path, &err),
     }
 }
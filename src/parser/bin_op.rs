@@ -1,95 +1,185 @@
 //! Parsers for binary operators.
 //!
-//! This module contains a set of functions `bin_op_<n>` where each `n` represents one level of
-//! precedence, i.e., the operators in `bin_op_5` have higher precedence than the operators in
-//! `bin_op_1`.
+//! Operators are ranked by precedence via [`BinOpExt::precedence`] and parsed by a single
+//! precedence-climbing parser, [`expr_bp`], rather than one function per precedence level.
 //!
-//! Each one of this parsers is used inside the [`binary_op`] submodule with the same numeric
-//! convention as here.
+//! [`BinOpExt::fixity`] controls associativity: most operators are left-associative, `**` is
+//! right-associative, and comparisons are non-associative.
 //!
-//! [`binary_op`]: crate::parser::node::binary_op
+//! `expr_bp` also collects [`Warning`]s for unparenthesized shift/arithmetic mixes, and handles
+//! `as` casts, whose right operand is a type rather than an expression.
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{char, space0},
-    combinator::{map, not, peek},
-    sequence::terminated,
+    character::complete::{char, satisfy, space0},
+    combinator::{fail, map, not, peek},
+    sequence::{delimited, preceded, terminated},
 };
 
 use log::debug;
 
-use pijama_ast::{BinOp, BinOp::*, Span};
+use pijama_ast::{BinOp, BinOp::*, Node, Span};
 
 use crate::parser::{
     helpers::{log_success, surrounded, with_context},
+    node::atom,
+    ty::ty,
     IResult,
 };
 
-/// Parser for the binary operators with precedence level 1.
+/// A non-fatal diagnostic produced while parsing an expression.
 ///
-/// These operators are `&&` and `||`.
+/// Unlike a parse error, a [`Warning`] doesn't stop parsing: it's returned alongside the `Node`
+/// it was found in by [`expr_bp`], so it only reaches the caller if that `Node` is kept, and can
+/// be surfaced the same way parse errors are, through `display_error` in `main.rs`.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    /// Where the operator that triggered the warning is.
+    pub span: Span,
+    /// A human-readable explanation, including the suggested parenthesization.
+    pub message: String,
+}
+
+/// Returns `true` for the shift operators, `false` for the additive/multiplicative ones.
+fn is_shift(op: BinOp) -> bool {
+    matches!(op, Shr | Shl)
+}
+
+/// Returns `true` for the additive/multiplicative operators that are easy to confuse with a
+/// shift when left unparenthesized.
+fn is_arithmetic(op: BinOp) -> bool {
+    matches!(op, Add | Sub | Mul | Div | Rem)
+}
+
+/// Returns the surface syntax of a [`BinOp`], e.g. `"<<"` for [`BinOp::Shl`].
 ///
-/// All the binary operators might be surrounded by zero or more spaces.
-pub fn bin_op_1(input: Span) -> IResult<BinOp> {
-    surrounded(
-        with_context(
-            "Expected logical operator (&&, ||)",
-            log_success(
-                alt((map(tag("&&"), |_| And), map(tag("||"), |_| Or))),
-                |op, loc| debug!("Parsed logical operator {:?} at {}", op, loc),
-            ),
+/// Used so diagnostics quote what the user actually typed instead of the enum variant name.
+fn symbol(op: BinOp) -> &'static str {
+    match op {
+        And => "&&",
+        Or => "||",
+        Lte => "<=",
+        Gte => ">=",
+        Lt => "<",
+        Gt => ">",
+        Eq => "==",
+        Neq => "!=",
+        BitAnd => "&",
+        BitOr => "|",
+        BitXor => "^",
+        Shr => ">>",
+        Shl => "<<",
+        Add => "+",
+        Sub => "-",
+        Mul => "*",
+        Div => "/",
+        Rem => "%",
+        Pow => "**",
+    }
+}
+
+/// Returns a [`Warning`] if `op` is a shift and `child_op` is an unparenthesized
+/// additive/multiplicative operator, or vice versa.
+fn warn_on_shift_arithmetic_mix(
+    op: BinOp,
+    op_span: Span,
+    child_op: BinOp,
+    child_parenthesized: bool,
+) -> Option<Warning> {
+    if child_parenthesized {
+        return None;
+    }
+    let mixed =
+        (is_shift(op) && is_arithmetic(child_op)) || (is_arithmetic(op) && is_shift(child_op));
+    if !mixed {
+        return None;
+    }
+    Some(Warning {
+        span: op_span,
+        message: format!(
+            "mixing `{}` and `{}` without parentheses; their precedence may surprise you, \
+             consider writing `(_ {} _)` to make the grouping explicit",
+            symbol(op),
+            symbol(child_op),
+            symbol(child_op)
         ),
-        space0,
-    )(input)
+    })
 }
 
-/// Parser for the binary operators with precedence level 2.
+/// The associativity of a [`BinOp`]: how it folds with another operator of the same precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixity {
+    Left,
+    Right,
+    None,
+}
+
+/// Precedence and associativity information for a [`BinOp`].
 ///
-/// These operators are `<=`, `>=`, `<`, `>`, `==` and `!=`.
+/// `BinOp` is defined in `pijama_ast`, so this is an extension trait rather than an inherent
+/// impl.
+pub trait BinOpExt {
+    /// Returns the precedence level of this operator. Higher numbers bind tighter.
+    fn precedence(&self) -> u8;
+
+    /// Returns the associativity of this operator.
+    fn fixity(&self) -> Fixity;
+}
+
+impl BinOpExt for BinOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            And | Or => 1,
+            Lte | Gte | Lt | Gt | Eq | Neq => 2,
+            BitAnd | BitOr | BitXor | Shr | Shl => 3,
+            Add | Sub => 4,
+            Mul | Div | Rem => 5,
+            // `as` casts sit between this level and `Pow`, see `CAST_PRECEDENCE`.
+            Pow => 7,
+        }
+    }
+
+    fn fixity(&self) -> Fixity {
+        // Comparisons are non-associative: `a == b == c` is a parse error, not a left-fold.
+        match self {
+            Lte | Gte | Lt | Gt | Eq | Neq => Fixity::None,
+            Pow => Fixity::Right,
+            _ => Fixity::Left,
+        }
+    }
+}
+
+/// Parser for any binary operator, at any precedence level.
 ///
-/// An additional check is done for `<` and `>` to be sure they are not the beginning of the `>>`
-/// and `<<` operators.
+/// `<` and `>` must not be the start of `<<` and `>>`, and `&` and `|` must not be the start of
+/// `&&` and `||`.
 ///
-/// All the binary operators might be surrounded by zero or more spaces.
-pub fn bin_op_2(input: Span) -> IResult<BinOp> {
+/// The operator may be surrounded by zero or more spaces.
+pub fn bin_op(input: Span) -> IResult<BinOp> {
     surrounded(
         with_context(
-            "Expected comparision operator (<=, >=, <, >, ==, !=)",
+            "Expected binary operator",
             log_success(
                 alt((
+                    map(tag("&&"), |_| And),
+                    map(tag("||"), |_| Or),
                     map(tag("<="), |_| Lte),
                     map(tag(">="), |_| Gte),
                     map(terminated(char('<'), peek(not(char('<')))), |_| Lt),
                     map(terminated(char('>'), peek(not(char('>')))), |_| Gt),
                     map(tag("=="), |_| Eq),
                     map(tag("!="), |_| Neq),
-                )),
-                |op, loc| debug!("Parsed comparison operator {:?} at {}", op, loc),
-            ),
-        ),
-        space0,
-    )(input)
-}
-
-/// Parser for the binary operators with precedence level 3.
-///
-/// These operators are `&`, `|`, `^`, `>>` and `<<`.
-///
-/// An additional check is done for `&` and `|` to be sure they are not the beginning of the `&&`
-/// and `||` operators.
-///
-/// All the binary operators might be surrounded by zero or more spaces.
-pub fn bin_op_3(input: Span) -> IResult<BinOp> {
-    surrounded(
-        with_context(
-            "Expected binary operator (&, |, ^, <<, >>)",
-            log_success(
-                alt((
                     map(terminated(char('&'), peek(not(char('&')))), |_| BitAnd),
                     map(terminated(char('|'), peek(not(char('|')))), |_| BitOr),
                     map(char('^'), |_| BitXor),
                     map(tag(">>"), |_| Shr),
                     map(tag("<<"), |_| Shl),
+                    map(char('+'), |_| Add),
+                    map(char('-'), |_| Sub),
+                    map(tag("**"), |_| Pow),
+                    map(char('*'), |_| Mul),
+                    map(char('/'), |_| Div),
+                    map(char('%'), |_| Rem),
                 )),
                 |op, loc| debug!("Parsed binary operator {:?} at {}", op, loc),
             ),
@@ -98,42 +188,306 @@ pub fn bin_op_3(input: Span) -> IResult<BinOp> {
     )(input)
 }
 
-/// Parser for the binary operators with precedence level 4.
+/// Precedence level for the `as` cast operator.
 ///
-/// These operators are `+` and `-`.
+/// This sits just above multiplication (level 5) but below `**` (level 7), and below
+/// unary operators and atoms, which are always parsed first as part of [`operand`].
+const CAST_PRECEDENCE: u8 = 6;
+
+/// Parser for the `as` keyword that introduces a cast.
 ///
-/// All the binary operators might be surrounded by zero or more spaces.
-pub fn bin_op_4(input: Span) -> IResult<BinOp> {
+/// Guarded with a trailing word-boundary check so it doesn't match the start of an identifier
+/// like `asleep`.
+fn cast_kw(input: Span) -> IResult<()> {
     surrounded(
-        with_context(
-            "Expected binary operator (+, -)",
-            log_success(
-                alt((map(char('+'), |_| Add), map(char('-'), |_| Sub))),
-                |op, loc| debug!("Parsed binary operator {:?} at {}", op, loc),
+        map(
+            terminated(
+                tag("as"),
+                peek(not(satisfy(|c: char| c.is_alphanumeric() || c == '_'))),
             ),
+            |_| (),
         ),
         space0,
     )(input)
 }
 
-/// Parser for the binary operators with precedence level 5.
+/// Parses a single operand: either an atom, or a parenthesized expression.
 ///
-/// These operators are `*`, `/` and `%`.
-///
-/// All the binary operators might be surrounded by zero or more spaces.
-pub fn bin_op_5(input: Span) -> IResult<BinOp> {
-    surrounded(
-        with_context(
-            "Expected binary operator (*, /, %)",
-            log_success(
-                alt((
-                    map(char('*'), |_| Mul),
-                    map(char('/'), |_| Div),
-                    map(char('%'), |_| Rem),
-                )),
-                |op, loc| debug!("Parsed binary operator {:?} at {}", op, loc),
+/// Alongside the node, this returns whether it came from explicit parentheses, since that's
+/// exactly what tells `(1 << 2) + 3` apart from `1 << 2 + 3` for
+/// [`warn_on_shift_arithmetic_mix`], plus any warnings collected while parsing it. The flag
+/// isn't attached to the returned `Node` itself; it only feeds that one check.
+fn operand(input: Span) -> IResult<(Node, bool, Vec<Warning>)> {
+    alt((
+        map(
+            delimited(
+                terminated(char('('), space0),
+                expr_bp,
+                preceded(space0, char(')')),
             ),
+            |(node, warnings)| (node, true, warnings),
         ),
-        space0,
-    )(input)
+        map(atom, |node| (node, false, Vec::new())),
+    ))(input)
+}
+
+/// Parses a binary expression using precedence climbing.
+///
+/// This is the entry point that replaces the fixed `bin_op_1`..`bin_op_5` recursive descent.
+/// Returns every [`Warning`] collected while building the parsed `Node`, alongside it.
+pub fn expr_bp(input: Span) -> IResult<(Node, Vec<Warning>)> {
+    let (rest, (node, _, warnings)) = expr_bp_min(input, 1)?;
+    Ok((rest, (node, warnings)))
+}
+
+/// Parses a single operand, then repeatedly folds in binary operators that bind at least as
+/// tightly as `min_bp`.
+///
+/// An operator weaker than `min_bp` is left unconsumed and the loop stops. Otherwise the
+/// right-hand side is parsed with one level higher `min_bp` for `Left`/`None` fixity, or the
+/// same level for `Right`, so only `Right`-fixed operators swallow another of their own
+/// precedence. A repeated `None`-fixed operator at the same precedence is a parse error instead.
+///
+/// Each fold checks its two sides for an unparenthesized shift/arithmetic mix, accumulating a
+/// [`Warning`]. The returned `bool` mirrors [`operand`]'s: `true` only if nothing was folded.
+fn expr_bp_min(input: Span, min_bp: u8) -> IResult<(Node, bool, Vec<Warning>)> {
+    let (mut rest, (mut lhs, mut lhs_parenthesized, mut warnings)) = operand(input)?;
+    let mut last_non_assoc: Option<u8> = None;
+
+    loop {
+        let before_op = rest;
+
+        if CAST_PRECEDENCE >= min_bp {
+            if let Ok((after_kw, _)) = cast_kw(rest) {
+                let (after_ty, ty) = ty(after_kw)?;
+                let span = lhs.span().merge(&ty.span());
+                lhs = Node::Cast {
+                    expr: Box::new(lhs),
+                    ty,
+                    span,
+                };
+                lhs_parenthesized = false;
+                rest = after_ty;
+                continue;
+            }
+        }
+
+        let (after_op, op) = match bin_op(rest) {
+            Ok(result) => result,
+            Err(_) => break,
+        };
+
+        let prec = op.precedence();
+
+        if prec < min_bp {
+            rest = before_op;
+            break;
+        }
+
+        if last_non_assoc == Some(prec) {
+            return with_context(
+                "Chained comparisons are not allowed, use parentheses to disambiguate",
+                fail,
+            )(before_op);
+        }
+
+        let next_min_bp = match op.fixity() {
+            Fixity::Left | Fixity::None => prec + 1,
+            Fixity::Right => prec,
+        };
+
+        let (after_rhs, (rhs, rhs_parenthesized, rhs_warnings)) =
+            expr_bp_min(after_op, next_min_bp)?;
+        warnings.extend(rhs_warnings);
+
+        if let Node::BinaryOp(child_op, ..) = &lhs {
+            warnings.extend(warn_on_shift_arithmetic_mix(
+                op,
+                before_op,
+                *child_op,
+                lhs_parenthesized,
+            ));
+        }
+        if let Node::BinaryOp(child_op, ..) = &rhs {
+            warnings.extend(warn_on_shift_arithmetic_mix(
+                op,
+                before_op,
+                *child_op,
+                rhs_parenthesized,
+            ));
+        }
+
+        let span = lhs.span().merge(&rhs.span());
+        lhs = Node::BinaryOp(op, Box::new(lhs), Box::new(rhs), span);
+        lhs_parenthesized = false;
+        rest = after_rhs;
+        last_non_assoc = if op.fixity() == Fixity::None {
+            Some(prec)
+        } else {
+            None
+        };
+    }
+
+    Ok((rest, (lhs, lhs_parenthesized, warnings)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> (Node, Vec<Warning>) {
+        expr_bp(Span::new(input))
+            .unwrap_or_else(|_| panic!("expected `{}` to parse successfully", input))
+            .1
+    }
+
+    fn assert_rejected(input: &str) {
+        assert!(
+            expr_bp(Span::new(input)).is_err(),
+            "expected `{}` to be a parse error",
+            input
+        );
+    }
+
+    #[test]
+    fn subtraction_is_left_associative() {
+        let (node, _) = parse("1 - 2 - 3");
+        match node {
+            Node::BinaryOp(Sub, lhs, rhs, _) => {
+                assert!(
+                    matches!(*lhs, Node::BinaryOp(Sub, ..)),
+                    "expected `1 - 2 - 3` to parse as `(1 - 2) - 3`, left side was {:?}",
+                    lhs
+                );
+                assert!(
+                    !matches!(*rhs, Node::BinaryOp(..)),
+                    "right side of the outer `-` should be the literal `3`, got {:?}",
+                    rhs
+                );
+            }
+            other => panic!("expected a `Sub` BinaryOp at the top, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        let (node, _) = parse("1 + 2 * 3");
+        match node {
+            Node::BinaryOp(Add, lhs, rhs, _) => {
+                assert!(
+                    !matches!(*lhs, Node::BinaryOp(..)),
+                    "left side of `+` should be the literal `1`, got {:?}",
+                    lhs
+                );
+                assert!(
+                    matches!(*rhs, Node::BinaryOp(Mul, ..)),
+                    "right side of `+` should be `2 * 3`, got {:?}",
+                    rhs
+                );
+            }
+            other => panic!("expected an `Add` BinaryOp at the top, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chained_comparisons_are_rejected() {
+        assert_rejected("a == b == c");
+        assert_rejected("a < b < c");
+    }
+
+    #[test]
+    fn pow_is_right_associative() {
+        let (node, _) = parse("2 ** 3 ** 2");
+        match node {
+            Node::BinaryOp(Pow, lhs, rhs, _) => {
+                assert!(
+                    !matches!(*lhs, Node::BinaryOp(..)),
+                    "left side of the outer `**` should be the literal `2`, got {:?}",
+                    lhs
+                );
+                assert!(
+                    matches!(*rhs, Node::BinaryOp(Pow, ..)),
+                    "expected `2 ** 3 ** 2` to parse as `2 ** (3 ** 2)`, right side was {:?}",
+                    rhs
+                );
+            }
+            other => panic!("expected a `Pow` BinaryOp at the top, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unparenthesized_shift_arithmetic_mix_warns() {
+        let (_, warnings) = parse("1 << 2 + 3");
+        assert_eq!(
+            warnings.len(),
+            1,
+            "expected exactly one warning for `1 << 2 + 3`, got {:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn parenthesized_shift_arithmetic_mix_does_not_warn() {
+        let (_, warnings) = parse("(1 << 2) + 3");
+        assert!(
+            warnings.is_empty(),
+            "expected no warnings for `(1 << 2) + 3`, got {:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn cast_binds_tighter_than_multiplication() {
+        let (node, _) = parse("2 * 3 as Int");
+        match node {
+            Node::BinaryOp(Mul, lhs, rhs, _) => {
+                assert!(
+                    !matches!(*lhs, Node::Cast { .. }),
+                    "left side of `*` should be the literal `2`, got {:?}",
+                    lhs
+                );
+                assert!(
+                    matches!(*rhs, Node::Cast { .. }),
+                    "expected `2 * 3 as Int` to parse as `2 * (3 as Int)`, right side was {:?}",
+                    rhs
+                );
+            }
+            other => panic!("expected a `Mul` BinaryOp at the top, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cast_binds_looser_than_pow() {
+        let (node, _) = parse("2 ** 3 as Int");
+        match node {
+            Node::Cast { expr, .. } => {
+                assert!(
+                    matches!(*expr, Node::BinaryOp(Pow, ..)),
+                    "expected `2 ** 3 as Int` to parse as `(2 ** 3) as Int`, cast operand was {:?}",
+                    expr
+                );
+            }
+            other => panic!("expected a `Cast` at the top, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cast_applies_before_a_later_pow() {
+        let (node, _) = parse("2 as Int ** 3");
+        match node {
+            Node::BinaryOp(Pow, lhs, rhs, _) => {
+                assert!(
+                    matches!(*lhs, Node::Cast { .. }),
+                    "expected `2 as Int ** 3` to parse as `(2 as Int) ** 3`, left side was {:?}",
+                    lhs
+                );
+                assert!(
+                    !matches!(*rhs, Node::Cast { .. } | Node::BinaryOp(..)),
+                    "right side of `**` should be the literal `3`, got {:?}",
+                    rhs
+                );
+            }
+            other => panic!("expected a `Pow` BinaryOp at the top, got {:?}", other),
+        }
+    }
 }